@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use data_encoding::HEXUPPER;
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// target average chunk size is 2^AVERAGE_SHIFT bytes; boundaries clamp to
+// [MIN_CHUNK_SIZE, MAX_CHUNK_SIZE] so a run of identical bytes (or a run that
+// never trips the mask) can't produce a degenerate tiny or unbounded chunk
+const AVERAGE_SHIFT: u32 = 13; // ~8 KiB average
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+const BOUNDARY_MASK: u64 = (1 << AVERAGE_SHIFT) - 1;
+
+/// A content-defined chunk of a file, as recorded alongside the whole-file
+/// hash so `plan_sync` can fall back to a block-level diff instead of
+/// re-copying a whole file that only changed in one place.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRecord {
+    pub offset: u64,
+    pub len: u32,
+    pub hash: String,
+}
+
+/// Split `path` into content-defined chunks using a gear-hash rolling
+/// window (the same family of rolling hash FastCDC and Proxmox's pxar
+/// chunker use): a boundary is declared once the chunk is at least
+/// `MIN_CHUNK_SIZE` long and the low `AVERAGE_SHIFT` bits of the rolling
+/// hash are all zero, with a hard cut at `MAX_CHUNK_SIZE` so pathological
+/// input (e.g. a long run of zeroes) can't produce an unbounded chunk.
+pub fn chunk_file(path: &Path) -> Result<Vec<ChunkRecord>> {
+    let table = gear_table();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut chunks = Vec::new();
+    let mut buffer = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut offset: u64 = 0;
+    let mut hash: u64 = 0;
+    let mut read_buf = [0u8; 8192];
+
+    loop {
+        let count = reader.read(&mut read_buf)?;
+        if count == 0 {
+            break;
+        }
+
+        for &byte in &read_buf[..count] {
+            buffer.push(byte);
+            hash = hash.wrapping_shl(1).wrapping_add(table[byte as usize]);
+
+            let at_boundary = buffer.len() >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0;
+            let at_max = buffer.len() >= MAX_CHUNK_SIZE;
+            if at_boundary || at_max {
+                chunks.push(finish_chunk(&buffer, offset));
+                offset += buffer.len() as u64;
+                buffer.clear();
+                hash = 0;
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        chunks.push(finish_chunk(&buffer, offset));
+    }
+
+    Ok(chunks)
+}
+
+fn finish_chunk(buffer: &[u8], offset: u64) -> ChunkRecord {
+    let mut context = DigestContext::new(&SHA256);
+    context.update(buffer);
+    ChunkRecord {
+        offset,
+        len: buffer.len() as u32,
+        hash: HEXUPPER.encode(context.finish().as_ref()),
+    }
+}
+
+// a fixed pseudo-random table mapping each byte value to a 64-bit constant,
+// built with splitmix64 so it's reproducible without pulling in a `rand`
+// dependency just for this
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E3779B97F4A7C15;
+    for (index, slot) in table.iter_mut().enumerate() {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state ^ (index as u64);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        *slot = z ^ (z >> 31);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_temp(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn chunks_cover_the_whole_file_contiguously() {
+        let path = write_temp("chunking_test_contiguous", &vec![7u8; 5 * MIN_CHUNK_SIZE]);
+        let chunks = chunk_file(&path).unwrap();
+
+        let mut expected_offset = 0u64;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, expected_offset);
+            expected_offset += chunk.len as u64;
+        }
+        assert_eq!(expected_offset, 5 * MIN_CHUNK_SIZE as u64);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn no_chunk_exceeds_the_max_size() {
+        let path = write_temp("chunking_test_max_size", &vec![0u8; 3 * MAX_CHUNK_SIZE]);
+        let chunks = chunk_file(&path).unwrap();
+
+        assert!(chunks.iter().all(|chunk| (chunk.len as usize) <= MAX_CHUNK_SIZE));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // a reproducible stand-in for `rand`, so the fixture has enough entropy
+    // that the rolling hash reliably finds boundaries instead of degenerating
+    // into one giant chunk (as a short or low-entropy buffer would)
+    fn pseudo_random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^= z >> 31;
+            bytes.extend_from_slice(&z.to_le_bytes());
+        }
+        bytes.truncate(len);
+        bytes
+    }
+
+    #[test]
+    fn a_local_edit_only_changes_the_chunks_touching_it() {
+        // well above the ~8 KiB average chunk size so a boundary reliably
+        // falls away from the edit point on both sides
+        let original = pseudo_random_bytes(20 * (1 << AVERAGE_SHIFT), 0xC0FFEE);
+        let mut edited = original.clone();
+        let edit_at = original.len() / 2;
+        edited[edit_at] ^= 0xFF;
+
+        let original_path = write_temp("chunking_test_original", &original);
+        let edited_path = write_temp("chunking_test_edited", &edited);
+
+        let original_chunks = chunk_file(&original_path).unwrap();
+        let edited_chunks = chunk_file(&edited_path).unwrap();
+
+        let original_hashes: std::collections::HashSet<_> =
+            original_chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        let changed = edited_chunks
+            .iter()
+            .filter(|chunk| !original_hashes.contains(&chunk.hash))
+            .count();
+
+        assert!(
+            changed < edited_chunks.len(),
+            "most chunks should be unaffected by a single-byte edit"
+        );
+
+        std::fs::remove_file(&original_path).unwrap();
+        std::fs::remove_file(&edited_path).unwrap();
+    }
+}
@@ -6,72 +6,299 @@ use ring::digest::SHA256;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read, Write};
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use walkdir::WalkDir;
 
-#[derive(Serialize, Deserialize, Debug)]
+mod chunking;
+mod syncignore;
+mod vfs;
+mod watch;
+use chunking::ChunkRecord;
+use syncignore::IgnoreMatcher;
+use vfs::{ChunkRange, Fs, RealFs};
+
+/// What kind of filesystem entry a `Record` describes, following Mercurial's
+/// status taxonomy of distinguishing "a path with content to sync" from
+/// "a path that needs special handling" rather than collapsing everything
+/// down to a file/directory bit.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Dir,
+    Symlink { target: PathBuf },
+    // a socket, device, fifo, or anything else we can stat but not sync
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Record {
     name: String,
     hash: Option<String>,
+    size: u64,
+    mtime: i64,
+    // per-chunk digests, only populated when `--chunked` is passed; lets
+    // `plan_sync` fall back to a block-level diff instead of a whole-file copy
+    chunks: Option<Vec<ChunkRecord>>,
+    kind: EntryKind,
+    mode: u32,
+}
+
+// a directory tree's records, keyed by the relative path of the directory
+// that holds them (the root is keyed by the empty string)
+type Entries = HashMap<String, Vec<Record>>;
+
+/// A path `map_directory` or watch mode couldn't make sense of: permission
+/// denied, a broken symlink, or an unsupported file type (socket, device,
+/// fifo). Collected instead of aborting the whole run over one bad path.
+#[derive(Debug, Clone)]
+struct BadPath {
+    path: PathBuf,
+    reason: String,
+}
+
+fn report_bad_paths(bad_paths: &[BadPath]) {
+    for bad_path in bad_paths {
+        eprintln!(
+            "warning: skipping `{}`: {}",
+            bad_path.path.display(),
+            bad_path.reason
+        );
+    }
 }
 
 // process reference directory and save state
-fn save_state(reference_directory: &str, state: &str) -> Result<()> {
-    let entries = map_directory(reference_directory)?;
+fn save_state(reference_directory: &str, state: &str, chunked: bool) -> Result<()> {
+    // reuse the previous state's hashes for files whose size/mtime haven't moved
+    let previous_entries = load_previous_state(state);
+    let ignore = IgnoreMatcher::load_for_directory(reference_directory)?;
+    let (entries, bad_paths) =
+        map_directory(reference_directory, previous_entries.as_ref(), &ignore, chunked)?;
+    report_bad_paths(&bad_paths);
+    write_state(state, &entries)
+}
 
-    // save directory map to file
+fn write_state(state: &str, entries: &Entries) -> Result<()> {
     let state_file =
         File::create(state).with_context(|| format!("Failed to save state to {}", state))?;
-    bincode::serialize_into(state_file, &entries)?;
-
+    bincode::serialize_into(state_file, entries)?;
     Ok(())
 }
 
-fn map_directory(directory: &str) -> Result<HashMap<String, Vec<Record>>> {
-    let mut records: HashMap<String, Vec<Record>> = HashMap::new();
+// best-effort load of a previously saved state; a missing or unreadable file
+// just means we fall back to hashing everything
+fn load_previous_state(state: &str) -> Option<Entries> {
+    let state_file = File::open(state).ok()?;
+    bincode::deserialize_from(state_file).ok()
+}
+
+fn map_directory(
+    directory: &str,
+    previous: Option<&Entries>,
+    ignore: &IgnoreMatcher,
+    chunked: bool,
+) -> Result<(Entries, Vec<BadPath>)> {
+    // treat anything modified in the same wall-clock second as "now" as unsure,
+    // since mtime resolution can't tell it apart from a change made after we
+    // started walking
+    let now = SystemTime::now();
+    let previous_lookup = build_previous_lookup(previous);
+
+    let mut records: Entries = HashMap::new();
     let mut files: Vec<(String, String, String)> = Vec::new();
+    let mut bad_paths: Vec<BadPath> = Vec::new();
     records.insert("".to_string(), Vec::new());
     let base = Path::new(directory);
-    for entry in WalkDir::new(directory).into_iter().skip(1) {
+
+    let mut walker = WalkDir::new(directory).into_iter();
+    walker.next(); // the root itself isn't a record
+
+    while let Some(entry) = walker.next() {
         let entry = entry?;
         let name = String::from(entry.file_name().to_string_lossy());
         let path = entry.path();
         let relative_parent = path.parent().unwrap().strip_prefix(base)?;
         let relative_self = relative_parent.join(&name);
+        let file_type = entry.file_type();
+
+        if ignore.is_ignored(&relative_self) {
+            if file_type.is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
 
         let relative_parent_string = String::from(relative_parent.to_string_lossy());
         let path_string = String::from(path.to_string_lossy());
 
-        if entry.file_type().is_dir() {
-            records.insert(String::from(relative_self.to_string_lossy()), Vec::new());
-            records
-                .get_mut(&String::from(relative_parent.to_string_lossy()))
-                .unwrap()
-                .push(Record { name, hash: None });
-        } else {
+        if file_type.is_dir() {
+            match fs::metadata(path) {
+                Ok(metadata) => {
+                    records.insert(String::from(relative_self.to_string_lossy()), Vec::new());
+                    records
+                        .get_mut(&relative_parent_string)
+                        .unwrap()
+                        .push(Record {
+                            name,
+                            hash: None,
+                            size: 0,
+                            mtime: 0,
+                            chunks: None,
+                            kind: EntryKind::Dir,
+                            mode: metadata.mode() & 0o7777,
+                        });
+                }
+                Err(err) => {
+                    bad_paths.push(BadPath {
+                        path: path.to_path_buf(),
+                        reason: err.to_string(),
+                    });
+                    walker.skip_current_dir();
+                }
+            }
+        } else if file_type.is_symlink() {
+            match build_symlink_record(&name, path) {
+                Ok(record) => records.get_mut(&relative_parent_string).unwrap().push(record),
+                Err(err) => bad_paths.push(BadPath {
+                    path: path.to_path_buf(),
+                    reason: err.to_string(),
+                }),
+            }
+        } else if file_type.is_file() {
             files.push((relative_parent_string, name, path_string));
+        } else {
+            bad_paths.push(BadPath {
+                path: path.to_path_buf(),
+                reason: "unsupported file type (socket, device, or fifo)".to_string(),
+            });
         }
     }
 
-    // calculate hashes in parallel
-    let file_records: Vec<_> = files
+    // calculate hashes in parallel, skipping files whose cached (size, mtime)
+    // still matches what's on disk; a file that can't be stat'd or read is
+    // reported as a bad path instead of aborting the whole walk
+    let file_results: Vec<_> = files
         .par_iter()
         .map(|(relative_parent, name, path)| {
-            let file_record = Record {
-                name: name.to_string(),
-                hash: Some(calculate_hash(Path::new(path)).unwrap()),
-            };
-            (relative_parent, file_record)
+            let previous = previous_lookup.get(&(relative_parent.as_str(), name.as_str()));
+            match build_file_record(name, Path::new(path), previous.copied(), chunked, now) {
+                Ok(record) => Ok((relative_parent, record)),
+                Err(err) => Err(BadPath {
+                    path: PathBuf::from(path),
+                    reason: err.to_string(),
+                }),
+            }
         })
         .collect();
 
-    for file_record in file_records {
-        records.get_mut(file_record.0).unwrap().push(file_record.1);
+    for result in file_results {
+        match result {
+            Ok((relative_parent, record)) => records.get_mut(relative_parent).unwrap().push(record),
+            Err(bad_path) => bad_paths.push(bad_path),
+        }
     }
 
-    Ok(records)
+    Ok((records, bad_paths))
+}
+
+// a symlink's "content" is the path it points at, not anything hashable;
+// captured via `read_link`/`symlink_metadata` so following the link is never
+// required (and a broken link is still recorded, just with no content)
+fn build_symlink_record(name: &str, path: &Path) -> Result<Record> {
+    let target = fs::read_link(path)
+        .with_context(|| format!("Failed to read symlink {}", path.display()))?;
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to stat symlink {}", path.display()))?;
+
+    Ok(Record {
+        name: name.to_string(),
+        hash: None,
+        size: 0,
+        mtime: 0,
+        chunks: None,
+        kind: EntryKind::Symlink { target },
+        mode: metadata.mode() & 0o7777,
+    })
+}
+
+// hash (and optionally chunk) a single file, reusing `previous`'s hash/chunks
+// when its cached (size, mtime) still matches what's on disk; shared between
+// the full `map_directory` walk and watch mode's per-event incremental update
+// so both paths get the same cache-hit behavior
+fn build_file_record(
+    name: &str,
+    path: &Path,
+    previous: Option<&Record>,
+    chunked: bool,
+    now: SystemTime,
+) -> Result<Record> {
+    let metadata = fs::metadata(path)?;
+    let size = metadata.len();
+    let mtime = mtime_nanos(&metadata)?;
+
+    let cached = previous
+        .filter(|record| record.size == size && record.mtime == mtime)
+        .filter(|_| !is_racy(mtime, now));
+
+    let hash = match cached.and_then(|record| record.hash.clone()) {
+        Some(hash) => hash,
+        None => calculate_hash(path)?,
+    };
+
+    // chunks are only needed in `--chunked` mode; reuse the cached ones on
+    // an unchanged file rather than re-chunking for nothing
+    let chunks = if !chunked {
+        None
+    } else {
+        match cached.and_then(|record| record.chunks.clone()) {
+            Some(chunks) => Some(chunks),
+            None => Some(chunking::chunk_file(path)?),
+        }
+    };
+
+    Ok(Record {
+        name: name.to_string(),
+        hash: Some(hash),
+        size,
+        mtime,
+        chunks,
+        kind: EntryKind::File,
+        mode: metadata.mode() & 0o7777,
+    })
+}
+
+fn build_previous_lookup(
+    previous: Option<&Entries>,
+) -> HashMap<(&str, &str), &Record> {
+    let mut lookup = HashMap::new();
+    if let Some(previous) = previous {
+        for (relative_parent, records) in previous {
+            for record in records {
+                lookup.insert((relative_parent.as_str(), record.name.as_str()), record);
+            }
+        }
+    }
+    lookup
+}
+
+fn mtime_nanos(metadata: &fs::Metadata) -> Result<i64> {
+    let duration = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(duration.as_nanos() as i64)
+}
+
+// a file is "racy" if its mtime falls in the same (coarse) second as `now`;
+// we can't trust a cache hit in that case since the file could have been
+// written again after the timestamp was captured
+fn is_racy(mtime: i64, now: SystemTime) -> bool {
+    let now_secs = now
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(i64::MAX);
+    let mtime_secs = mtime / 1_000_000_000;
+    mtime_secs >= now_secs
 }
 
 fn calculate_hash(path: &Path) -> Result<String> {
@@ -91,20 +318,187 @@ fn calculate_hash(path: &Path) -> Result<String> {
     Ok(HEXUPPER.encode(context.finish().as_ref()))
 }
 
-// load saved state, process target directory and output the diff
-fn sync_directory(
+/// A single step of a sync plan. Kept as data (rather than a formatted
+/// string) so the same plan can be printed, executed through an `Fs`, or
+/// inspected by tests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operation {
+    Create(PathBuf),
+    Copy { from: PathBuf, to: PathBuf },
+    Move { from: PathBuf, to: PathBuf },
+    // patch a file already present at `to`, copying only the byte ranges
+    // (from `from`) that changed, as determined by a chunk-level diff
+    CopyChunks {
+        from: PathBuf,
+        to: PathBuf,
+        ranges: Vec<ChunkRange>,
+    },
+    // create a symlink, or replace a non-symlink path with one
+    Symlink { path: PathBuf, target: PathBuf },
+    // an existing symlink's target changed; re-point it
+    Retarget { path: PathBuf, target: PathBuf },
+    // a file or directory's permission bits changed
+    Chmod { path: PathBuf, mode: u32 },
+    DeleteFile(PathBuf),
+    DeleteDir(PathBuf),
+}
+
+impl Operation {
+    fn describe(&self) -> String {
+        match self {
+            Operation::Create(path) => format!("create `{}`", path.display()),
+            Operation::Copy { to, .. } => format!("copy `{}`", to.display()),
+            Operation::Move { from, to } => {
+                format!("move `{}` -> `{}`", from.display(), to.display())
+            }
+            Operation::CopyChunks { to, ranges, .. } => format!(
+                "copy {} changed chunk(s) of `{}`",
+                ranges.len(),
+                to.display()
+            ),
+            Operation::Symlink { path, target } => {
+                format!("symlink `{}` -> `{}`", path.display(), target.display())
+            }
+            Operation::Retarget { path, target } => format!(
+                "retarget `{}` -> `{}`",
+                path.display(),
+                target.display()
+            ),
+            Operation::Chmod { path, mode } => format!("chmod {:o} `{}`", mode, path.display()),
+            Operation::DeleteFile(path) => format!("delete `{}`", path.display()),
+            Operation::DeleteDir(path) => format!("delete `{}`", path.display()),
+        }
+    }
+
+    fn target_path(&self) -> &Path {
+        match self {
+            Operation::Create(path) => path,
+            Operation::Copy { to, .. } => to,
+            Operation::Move { to, .. } => to,
+            Operation::CopyChunks { to, .. } => to,
+            Operation::Symlink { path, .. } => path,
+            Operation::Retarget { path, .. } => path,
+            Operation::Chmod { path, .. } => path,
+            Operation::DeleteFile(path) => path,
+            Operation::DeleteDir(path) => path,
+        }
+    }
+}
+
+// diff a reference file's chunks against a target file's chunks, yielding
+// the byte ranges that need to be (re)written for `to` to match `from`; a
+// reference chunk counts as already present when a target chunk exists at
+// the exact same offset with the exact same hash, so an insertion/deletion
+// upstream of an edit (which shifts every later offset) safely degrades to
+// re-copying everything after it rather than silently under-copying
+fn diff_chunks(reference: &[ChunkRecord], target: &[ChunkRecord]) -> Vec<ChunkRange> {
+    let target_by_offset: HashMap<u64, &str> = target
+        .iter()
+        .map(|chunk| (chunk.offset, chunk.hash.as_str()))
+        .collect();
+
+    let mut ranges: Vec<ChunkRange> = Vec::new();
+    for chunk in reference {
+        if target_by_offset.get(&chunk.offset) == Some(&chunk.hash.as_str()) {
+            continue;
+        }
+
+        match ranges.last_mut() {
+            Some(last) if last.offset + last.len as u64 == chunk.offset => {
+                last.len += chunk.len;
+            }
+            _ => ranges.push(ChunkRange {
+                offset: chunk.offset,
+                len: chunk.len,
+            }),
+        }
+    }
+    ranges
+}
+
+// only chunk-diffable when both the reference record and the current target
+// file were walked in `--chunked` mode
+// whether two records describe the same kind of entry, ignoring a
+// symlink's target (a target mismatch is a `Retarget`, not a delete+recreate)
+fn same_kind(a: &EntryKind, b: &EntryKind) -> bool {
+    matches!(
+        (a, b),
+        (EntryKind::Dir, EntryKind::Dir)
+            | (EntryKind::Symlink { .. }, EntryKind::Symlink { .. })
+            | (EntryKind::File, EntryKind::File)
+            | (EntryKind::Other, EntryKind::Other)
+    )
+}
+
+fn chunk_diff(reference_record: &Record, target_record: &Record) -> Option<Vec<ChunkRange>> {
+    let reference_chunks = reference_record.chunks.as_ref()?;
+    let target_chunks = target_record.chunks.as_ref()?;
+    Some(diff_chunks(reference_chunks, target_chunks))
+}
+
+// a reference file this run needs to place into the target tree, not yet
+// resolved to a `Copy` (from the reference directory) or a `Move` (from an
+// equal-hash file already sitting somewhere else in the target tree); the
+// reference's mode travels along so a `Move` can still get a corrective
+// `Chmod` when the rename source's permissions don't already match
+struct PendingCopy {
+    to: PathBuf,
+    relative_self: PathBuf,
+    hash: String,
+    mode: u32,
+}
+
+// a target path this run wants to remove; kept alongside its hash (for
+// files) so it can double as a rename source instead, and its mode so a
+// rename can be detected as needing a corrective `Chmod` too
+struct PendingDelete {
+    path: PathBuf,
+    is_dir: bool,
+    hash: Option<String>,
+    mode: u32,
+}
+
+// load saved state, diff it against the target directory and build a plan;
+// `previous_target` lets a repeated caller (namely `--watch`'s loop) reuse
+// the target side's cached hashes too, the same way `map_directory` already
+// does for the reference side, instead of re-hashing the whole target tree
+// on every iteration
+fn plan_sync(
     target_directory: &str,
     state: &str,
-    mut out_writer: std::boxed::Box<dyn std::io::Write>,
-) -> Result<()> {
+    reference_directory: Option<&str>,
+    chunked: bool,
+    fs: &dyn Fs,
+    previous_target: Option<&Entries>,
+) -> Result<(Vec<Operation>, Entries)> {
     let state_file =
         File::open(state).with_context(|| format!("Failed to open state in {}", state))?;
-    let entries: HashMap<String, Vec<Record>> = bincode::deserialize_from(state_file)?;
-    let mut operations = Vec::new();
+    let entries: Entries = bincode::deserialize_from(state_file)?;
+    let mut creates = Vec::new();
+    let mut pending_copies = Vec::new();
+    let mut pending_deletes = Vec::new();
+    // paths where the target's entry kind doesn't match the reference's (a
+    // file sitting where the reference has a directory, or vice versa); the
+    // stale entry there (and, for a stale directory, everything under it)
+    // must be fully removed before the recreated entry is written, so these
+    // are kept apart from `pending_deletes` and sequenced ahead of every
+    // create/copy instead of after
+    let mut kind_mismatch_paths = Vec::new();
     let base = Path::new(target_directory);
     let mut processed_parents: HashMap<String, bool> = HashMap::new();
 
-    let target_entries = map_directory(target_directory)?;
+    // the ignore rules come from the reference side (the same matcher
+    // `save_state` used to build `entries`), not the target: a target-only
+    // matcher would judge a file like `local.cache` by rules that haven't
+    // been copied over yet, which on a first sync is never, and schedule a
+    // delete for something `.syncignore` says to leave alone
+    let ignore = match reference_directory {
+        Some(directory) => IgnoreMatcher::load_for_directory(directory)?,
+        None => IgnoreMatcher::load_for_directory(target_directory)?,
+    };
+    let (target_entries, bad_paths) =
+        map_directory(target_directory, previous_target, &ignore, chunked)?;
+    report_bad_paths(&bad_paths);
 
     for (relative_parent, records) in &target_entries {
         for target_record in records {
@@ -115,13 +509,16 @@ fn sync_directory(
                     if !processed_parents.contains_key(relative_parent) {
                         processed_parents.insert(relative_parent.to_string(), true);
                         for record in records {
-                            let path = Path::new(base);
-                            let relative_self = path.join(relative_parent).join(&record.name);
-                            if !relative_self.exists() {
-                                let mut copy_operations =
-                                    copy_record(record, &relative_parent, &entries)?;
-                                copy_operations.reverse();
-                                operations.append(&mut copy_operations);
+                            let path = base.join(relative_parent).join(&record.name);
+                            if fs.metadata(&path)?.is_none() {
+                                collect_pending_copies(
+                                    record,
+                                    relative_parent,
+                                    &entries,
+                                    base,
+                                    &mut creates,
+                                    &mut pending_copies,
+                                )?;
                             }
                         }
                     }
@@ -130,60 +527,586 @@ fn sync_directory(
                         .iter()
                         .find(|&record| record.name == target_record.name)
                     {
-                        let target_is_dir = target_record.hash.is_none();
-                        // delete record if the type doesn't match
-                        let record_is_dir = record.hash.is_none();
-                        if target_is_dir != record_is_dir {
-                            operations.push(format!("delete `{}`", relative_self.display()))
+                        // delete and recreate if the entry's kind doesn't match
+                        if !same_kind(&record.kind, &target_record.kind) {
+                            kind_mismatch_paths.push(base.join(&relative_self));
+                            push_delete(
+                                &mut pending_deletes,
+                                base.join(&relative_self),
+                                matches!(target_record.kind, EntryKind::Dir),
+                                target_record.hash.clone(),
+                                target_record.mode,
+                            );
+                            collect_pending_copies(
+                                record,
+                                relative_parent,
+                                &entries,
+                                base,
+                                &mut creates,
+                                &mut pending_copies,
+                            )?;
+                            continue;
                         }
-                        // do nothing for directories
-                        else if !target_is_dir {
-                            // copy from source if hashes do not match
-                            if target_record.hash.as_ref().unwrap() != record.hash.as_ref().unwrap()
-                            {
-                                operations.push(format!("copy `{}`", relative_self.display()))
+
+                        match &record.kind {
+                            EntryKind::Dir => {}
+                            EntryKind::Symlink { target: reference_target } => {
+                                let target_target = match &target_record.kind {
+                                    EntryKind::Symlink { target } => target,
+                                    _ => unreachable!("kind checked above"),
+                                };
+                                if reference_target != target_target {
+                                    creates.push(Operation::Retarget {
+                                        path: base.join(&relative_self),
+                                        target: reference_target.clone(),
+                                    });
+                                }
+                            }
+                            EntryKind::File => {
+                                // copy from source if hashes do not match
+                                if target_record.hash.as_ref().unwrap()
+                                    != record.hash.as_ref().unwrap()
+                                {
+                                    // if both sides were chunked, patch just the
+                                    // changed ranges instead of the whole file
+                                    match chunk_diff(record, target_record) {
+                                        Some(ranges) => creates.push(Operation::CopyChunks {
+                                            from: resolve_from(reference_directory, &relative_self),
+                                            to: base.join(&relative_self),
+                                            ranges,
+                                        }),
+                                        None => pending_copies.push(PendingCopy {
+                                            to: base.join(&relative_self),
+                                            relative_self: relative_self.clone(),
+                                            hash: record.hash.clone().unwrap(),
+                                            mode: record.mode,
+                                        }),
+                                    }
+                                }
                             }
+                            EntryKind::Other => {}
+                        }
+
+                        if record.mode != target_record.mode {
+                            creates.push(Operation::Chmod {
+                                path: base.join(&relative_self),
+                                mode: record.mode,
+                            });
                         }
                     } else {
-                        operations.push(format!("delete `{}`", relative_self.display()));
+                        push_delete(
+                            &mut pending_deletes,
+                            base.join(&relative_self),
+                            matches!(target_record.kind, EntryKind::Dir),
+                            target_record.hash.clone(),
+                            target_record.mode,
+                        );
                     }
                 }
-                None => operations.push(format!("delete `{}`", relative_self.display())),
+                None => push_delete(
+                    &mut pending_deletes,
+                    base.join(&relative_self),
+                    matches!(target_record.kind, EntryKind::Dir),
+                    target_record.hash.clone(),
+                    target_record.mode,
+                ),
             }
         }
     }
 
-    for operation in operations.iter().rev() {
-        writeln!(out_writer, "{}", operation)?;
+    let (moves, copies, deletes) = resolve_renames(
+        pending_copies,
+        pending_deletes,
+        reference_directory,
+        &kind_mismatch_paths,
+    );
+
+    let mut creates_and_copies = creates;
+    creates_and_copies.extend(moves);
+    creates_and_copies.extend(copies);
+
+    // creates/copies/moves happen parent-first (so a directory exists before
+    // its children are placed into it); deletes happen child-first (so a
+    // directory is empty by the time we try to remove it)
+    creates_and_copies.sort_by_key(|op| op.target_path().components().count());
+
+    // a delete whose path is (or is nested under) a kind-mismatch path must
+    // finish before the recreate at that same path runs, or `RealFs` fails
+    // outright (`create_dir_all` sees a file still there, `copy` sees a
+    // non-empty directory); everything else can stay after creates/copies as
+    // before, since it never shares a path with one
+    let (mut mismatch_deletes, mut other_deletes): (Vec<_>, Vec<_>) = deletes
+        .into_iter()
+        .partition(|op| path_is_under_any(op.target_path(), &kind_mismatch_paths));
+    mismatch_deletes.sort_by_key(|op| std::cmp::Reverse(op.target_path().components().count()));
+    other_deletes.sort_by_key(|op| std::cmp::Reverse(op.target_path().components().count()));
+
+    let mut plan = mismatch_deletes;
+    plan.extend(creates_and_copies);
+    plan.extend(other_deletes);
+    Ok((plan, target_entries))
+}
+
+// match pending copies against files that are about to be deleted anyway: if
+// an equal-hash file already exists somewhere in the target tree, relocate
+// it instead of deleting the original and re-copying the content
+fn resolve_renames(
+    pending_copies: Vec<PendingCopy>,
+    pending_deletes: Vec<PendingDelete>,
+    reference_directory: Option<&str>,
+    kind_mismatch_paths: &[PathBuf],
+) -> (Vec<Operation>, Vec<Operation>, Vec<Operation>) {
+    let mut hash_to_delete_candidates: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, delete) in pending_deletes.iter().enumerate() {
+        // a delete at or under a kind-mismatch path must run before every
+        // create/copy; turning it into a rename source would instead make
+        // it a `Move`, which sorts and runs alongside ordinary
+        // creates/copies and so could land after the create it (or its
+        // ancestor directory's recreate) was supposed to clear the way for
+        if path_is_under_any(&delete.path, kind_mismatch_paths) {
+            continue;
+        }
+        if let Some(hash) = &delete.hash {
+            hash_to_delete_candidates
+                .entry(hash.as_str())
+                .or_default()
+                .push(index);
+        }
     }
 
-    Ok(())
+    let mut consumed = vec![false; pending_deletes.len()];
+    let mut moves = Vec::new();
+    let mut copies = Vec::new();
+
+    for pending in pending_copies {
+        let destination_parent = pending.to.parent().unwrap_or_else(|| Path::new(""));
+        let candidate = hash_to_delete_candidates
+            .get(pending.hash.as_str())
+            .into_iter()
+            .flatten()
+            .filter(|&&index| !consumed[index])
+            .map(|&index| (index, &pending_deletes[index].path))
+            // tie-break on the candidate's own path rather than its index
+            // into `pending_deletes`: that index comes from iterating an
+            // `Entries` `HashMap` in `plan_sync`, whose default hasher is
+            // randomly seeded per process, so two equal-`shared_prefix_len`
+            // candidates (duplicate or empty files in sibling directories,
+            // say) could otherwise pick a different rename source on every
+            // run against the same trees
+            .max_by_key(|(_, path)| {
+                (
+                    shared_prefix_len(path.parent().unwrap_or_else(|| Path::new("")), destination_parent),
+                    std::cmp::Reverse(path.as_os_str().to_owned()),
+                )
+            });
+
+        match candidate {
+            Some((index, path)) => {
+                consumed[index] = true;
+                let source_mode = pending_deletes[index].mode;
+                moves.push(Operation::Move {
+                    from: path.clone(),
+                    to: pending.to.clone(),
+                });
+                // a rename only relocates bytes; if the reference's
+                // permissions differ from what the moved file already has,
+                // correct them in the same pass instead of waiting for the
+                // next `--sync` to notice the mismatch
+                if source_mode != pending.mode {
+                    moves.push(Operation::Chmod {
+                        path: pending.to,
+                        mode: pending.mode,
+                    });
+                }
+            }
+            None => copies.push(Operation::Copy {
+                from: resolve_from(reference_directory, &pending.relative_self),
+                to: pending.to,
+            }),
+        }
+    }
+
+    let deletes = pending_deletes
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !consumed[*index])
+        .map(|(_, delete)| {
+            if delete.is_dir {
+                Operation::DeleteDir(delete.path)
+            } else {
+                Operation::DeleteFile(delete.path)
+            }
+        })
+        .collect();
+
+    (moves, copies, deletes)
+}
+
+// number of path components `a` and `b` share as a common prefix; used to
+// pick the rename candidate whose original location is "closest" to where
+// the file is being moved to, when several candidates share a hash
+fn shared_prefix_len(a: &Path, b: &Path) -> usize {
+    a.components()
+        .zip(b.components())
+        .take_while(|(left, right)| left == right)
+        .count()
 }
 
-fn copy_record(
+// whether `path` is one of `roots` or sits somewhere underneath one
+fn path_is_under_any(path: &Path, roots: &[PathBuf]) -> bool {
+    roots.iter().any(|root| path == root || path.starts_with(root))
+}
+
+fn push_delete(
+    deletes: &mut Vec<PendingDelete>,
+    path: PathBuf,
+    is_dir: bool,
+    hash: Option<String>,
+    mode: u32,
+) {
+    deletes.push(PendingDelete {
+        path,
+        is_dir,
+        hash,
+        mode,
+    });
+}
+
+fn resolve_from(reference_directory: Option<&str>, relative: &Path) -> PathBuf {
+    match reference_directory {
+        Some(directory) => Path::new(directory).join(relative),
+        None => relative.to_path_buf(),
+    }
+}
+
+fn collect_pending_copies(
     record: &Record,
     relative_parent: &str,
-    entries: &HashMap<String, Vec<Record>>,
-) -> Result<Vec<String>> {
-    let mut operations = Vec::new();
-    let relative_self = String::from(
-        Path::new(relative_parent)
-            .join(&record.name)
-            .to_string_lossy(),
-    );
-    if record.hash.is_none() {
-        operations.push(format!("create `{}`", relative_self));
-        for record in entries.get(&relative_self).unwrap() {
-            operations.append(&mut copy_record(record, &relative_self, &entries)?);
+    entries: &Entries,
+    target_base: &Path,
+    creates: &mut Vec<Operation>,
+    pending_copies: &mut Vec<PendingCopy>,
+) -> Result<()> {
+    let relative_self = Path::new(relative_parent).join(&record.name);
+    let to = target_base.join(&relative_self);
+    match &record.kind {
+        EntryKind::Dir => {
+            creates.push(Operation::Create(to));
+            let relative_self_string = String::from(relative_self.to_string_lossy());
+            for child in entries.get(&relative_self_string).unwrap() {
+                collect_pending_copies(
+                    child,
+                    &relative_self_string,
+                    entries,
+                    target_base,
+                    creates,
+                    pending_copies,
+                )?;
+            }
+        }
+        // a symlink's "content" is its target, not a hash, so it can't be
+        // matched against a delete the way a file rename candidate can
+        EntryKind::Symlink { target } => creates.push(Operation::Symlink {
+            path: to,
+            target: target.clone(),
+        }),
+        EntryKind::File => pending_copies.push(PendingCopy {
+            to,
+            relative_self,
+            hash: record.hash.clone().unwrap(),
+            mode: record.mode,
+        }),
+        EntryKind::Other => {}
+    }
+    Ok(())
+}
+
+fn print_plan(plan: &[Operation], mut out_writer: Box<dyn Write>) -> Result<()> {
+    for operation in plan {
+        writeln!(out_writer, "{}", operation.describe())?;
+    }
+    Ok(())
+}
+
+fn apply_plan(fs: &dyn Fs, plan: &[Operation]) -> Result<()> {
+    for operation in plan {
+        match operation {
+            Operation::Create(path) => fs.create_dir(path)?,
+            Operation::Copy { from, to } => fs.copy_file(from, to)?,
+            Operation::Move { from, to } => fs.rename(from, to)?,
+            Operation::CopyChunks { from, to, ranges } => fs.copy_chunks(from, to, ranges)?,
+            Operation::Symlink { path, target } => fs.create_symlink(path, target)?,
+            Operation::Retarget { path, target } => {
+                fs.remove_file(path)?;
+                fs.create_symlink(path, target)?;
+            }
+            Operation::Chmod { path, mode } => fs.set_mode(path, *mode)?,
+            Operation::DeleteFile(path) => fs.remove_file(path)?,
+            Operation::DeleteDir(path) => fs.remove_dir(path)?,
         }
+    }
+    Ok(())
+}
+
+// one capture-plan-act cycle, shared by the one-shot `--sync` path and every
+// iteration of `--watch`'s loop; `previous_target` and the returned state let
+// a repeated caller carry the target side's hash cache from one cycle to the
+// next instead of paying for a full rehash of the target tree every time
+#[allow(clippy::too_many_arguments)]
+fn run_plan_and_act(
+    target_directory: &str,
+    state_file_path: &str,
+    reference_directory: Option<&str>,
+    chunked: bool,
+    apply: bool,
+    output: Option<&str>,
+    fs: &dyn Fs,
+    previous_target: Option<&Entries>,
+) -> Result<Entries> {
+    let (plan, target_entries) = plan_sync(
+        target_directory,
+        state_file_path,
+        reference_directory,
+        chunked,
+        fs,
+        previous_target,
+    )?;
+
+    if apply {
+        apply_plan(fs, &plan)?;
     } else {
-        operations.push(format!("copy `{}`", relative_self));
+        let out_writer = match output {
+            Some(output) => Box::new(File::create(Path::new(output))?) as Box<dyn Write>,
+            None => Box::new(std::io::stdout()) as Box<dyn Write>,
+        };
+        print_plan(&plan, out_writer)?;
     }
-    Ok(operations)
+
+    Ok(target_entries)
+}
+
+// capture an initial state, then keep `state_file_path` (and therefore the
+// sync plan) up to date as the reference directory changes, turning the
+// tool from a one-shot planner into a live mirror
+fn run_watch(
+    target_directory: &str,
+    reference_directory: &str,
+    state_file_path: &str,
+    chunked: bool,
+    apply: bool,
+    output: Option<&str>,
+    fs: &dyn Fs,
+) -> Result<()> {
+    let ignore = IgnoreMatcher::load_for_directory(reference_directory)?;
+    let (mut entries, bad_paths) = map_directory(
+        reference_directory,
+        load_previous_state(state_file_path).as_ref(),
+        &ignore,
+        chunked,
+    )?;
+    report_bad_paths(&bad_paths);
+    write_state(state_file_path, &entries)?;
+    let mut target_entries = run_plan_and_act(
+        target_directory,
+        state_file_path,
+        Some(reference_directory),
+        chunked,
+        apply,
+        output,
+        fs,
+        None,
+    )?;
+
+    let batches = watch::watch(Path::new(reference_directory), Duration::from_millis(300))?;
+    for paths in batches {
+        apply_watch_paths(&mut entries, reference_directory, &paths, &ignore, chunked)?;
+        write_state(state_file_path, &entries)?;
+        target_entries = run_plan_and_act(
+            target_directory,
+            state_file_path,
+            Some(reference_directory),
+            chunked,
+            apply,
+            output,
+            fs,
+            Some(&target_entries),
+        )?;
+    }
+
+    Ok(())
+}
+
+// update the in-memory state for exactly the paths a watch batch reported
+// changed, re-stat'ing (and re-hashing, via `build_file_record`'s own cache
+// check) only those paths instead of re-walking the whole reference tree
+fn apply_watch_paths(
+    entries: &mut Entries,
+    reference_directory: &str,
+    paths: &[PathBuf],
+    ignore: &IgnoreMatcher,
+    chunked: bool,
+) -> Result<()> {
+    let base = Path::new(reference_directory);
+    let now = SystemTime::now();
+
+    for path in paths {
+        let relative = match path.strip_prefix(base) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => continue, // the watched root itself, or a path outside it
+        };
+        let relative_parent = relative.parent().unwrap_or_else(|| Path::new(""));
+        let name = match relative.file_name() {
+            Some(name) => String::from(name.to_string_lossy()),
+            None => continue,
+        };
+        let relative_parent_string = String::from(relative_parent.to_string_lossy());
+        let relative_self_string = String::from(relative.to_string_lossy());
+
+        if ignore.is_ignored(relative) {
+            remove_record(entries, &relative_parent_string, &name);
+            continue;
+        }
+
+        // `symlink_metadata` so a symlink is classified as one instead of
+        // being followed to whatever (or nothing) it points at
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                // a directory can appear via a single atomic event (`mv
+                // other/ watched/newdir`, an archive extracted in one shot)
+                // with files already inside it; `notify`'s inotify backend
+                // only starts watching it from here on and never synthesizes
+                // `Create` events for what's already there, so walk its
+                // subtree now instead of recording it as empty
+                let path_string = String::from(path.to_string_lossy());
+                let (sub_entries, bad_paths) =
+                    map_directory(&path_string, None, ignore, chunked)?;
+                report_bad_paths(&bad_paths);
+                merge_subtree(entries, &relative_self_string, sub_entries);
+                upsert_record(
+                    entries,
+                    &relative_parent_string,
+                    Record {
+                        name,
+                        hash: None,
+                        size: 0,
+                        mtime: 0,
+                        chunks: None,
+                        kind: EntryKind::Dir,
+                        mode: metadata.mode() & 0o7777,
+                    },
+                );
+            }
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                // a symlink replacing what used to be a directory at this
+                // path leaves that directory's subtree behind otherwise
+                let was_dir = entries
+                    .get(&relative_parent_string)
+                    .and_then(|records| records.iter().find(|record| record.name == name))
+                    .is_some_and(|record| matches!(record.kind, EntryKind::Dir));
+                if was_dir {
+                    purge_subtree(entries, &relative_self_string);
+                }
+                // a bad path here (the link vanishing or losing permissions
+                // between the stat above and the read) must not kill the
+                // whole `--watch` process, the same as `map_directory`
+                match build_symlink_record(&name, path) {
+                    Ok(record) => upsert_record(entries, &relative_parent_string, record),
+                    Err(err) => report_bad_paths(&[BadPath {
+                        path: path.clone(),
+                        reason: err.to_string(),
+                    }]),
+                }
+            }
+            Ok(metadata) if metadata.is_file() => {
+                let previous = entries
+                    .get(&relative_parent_string)
+                    .and_then(|records| records.iter().find(|record| record.name == name))
+                    .cloned();
+                // a file replacing what used to be a directory at this path
+                // leaves that directory's subtree behind otherwise
+                if previous
+                    .as_ref()
+                    .is_some_and(|record| matches!(record.kind, EntryKind::Dir))
+                {
+                    purge_subtree(entries, &relative_self_string);
+                }
+                // same as above: an unreadable or newly-inaccessible file
+                // (e.g. chmod 000'd right after the event fires) is reported
+                // and skipped instead of aborting the watch loop
+                match build_file_record(&name, path, previous.as_ref(), chunked, now) {
+                    Ok(record) => upsert_record(entries, &relative_parent_string, record),
+                    Err(err) => report_bad_paths(&[BadPath {
+                        path: path.clone(),
+                        reason: err.to_string(),
+                    }]),
+                }
+            }
+            Ok(_) => report_bad_paths(&[BadPath {
+                path: path.clone(),
+                reason: "unsupported file type (socket, device, or fifo)".to_string(),
+            }]),
+            Err(_) => remove_record(entries, &relative_parent_string, &name),
+        }
+    }
+
+    Ok(())
+}
+
+// fold a freshly-walked subtree (keyed relative to the directory that was
+// walked) into `entries`, re-keying each entry under `prefix` so it lands at
+// the same place a full `map_directory` of the reference root would have
+// put it
+fn merge_subtree(entries: &mut Entries, prefix: &str, sub_entries: Entries) {
+    for (relative, records) in sub_entries {
+        let combined = if relative.is_empty() {
+            prefix.to_string()
+        } else if prefix.is_empty() {
+            relative
+        } else {
+            format!("{}/{}", prefix, relative)
+        };
+        entries.insert(combined, records);
+    }
+}
+
+fn upsert_record(entries: &mut Entries, parent: &str, record: Record) {
+    let records = entries.entry(parent.to_string()).or_default();
+    match records.iter_mut().find(|existing| existing.name == record.name) {
+        Some(existing) => *existing = record,
+        None => records.push(record),
+    }
+}
+
+fn remove_record(entries: &mut Entries, parent: &str, name: &str) {
+    let removed_dir = match entries.get_mut(parent) {
+        Some(records) => {
+            let was_dir = records
+                .iter()
+                .any(|record| record.name == name && matches!(record.kind, EntryKind::Dir));
+            records.retain(|record| record.name != name);
+            was_dir
+        }
+        None => false,
+    };
+
+    if removed_dir {
+        let relative = if parent.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", parent, name)
+        };
+        purge_subtree(entries, &relative);
+    }
+}
+
+// drop a directory record's own (now empty of purpose) subtree entry, along
+// with any entries nested under it
+fn purge_subtree(entries: &mut Entries, relative: &str) {
+    let nested_prefix = format!("{}/", relative);
+    entries.retain(|key, _| key != relative && !key.starts_with(&nested_prefix));
 }
 
 // nice-to-haves:
-// - tests
 // - parallelism
 // - structopt
 fn main() -> Result<()> {
@@ -217,6 +1140,31 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .help("A file with a reference state. Defauls to 'state' in the current directory."),
         )
+        .arg(
+            Arg::with_name("apply")
+                .short("a")
+                .long("apply")
+                .help("When present with --sync, performs the sync operations instead of just printing them."),
+        )
+        .arg(
+            Arg::with_name("reference-directory")
+                .short("R")
+                .long("reference-directory")
+                .takes_value(true)
+                .help("The reference directory to copy files from; required by --apply."),
+        )
+        .arg(
+            Arg::with_name("chunked")
+                .short("c")
+                .long("chunked")
+                .help("Record per-chunk hashes so a changed file is patched with a block-level diff instead of copied whole."),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .short("w")
+                .long("watch")
+                .help("When present with --sync, keep running and re-plan (or re-apply) as --reference-directory changes instead of running once."),
+        )
         .get_matches();
 
     let mut default_state_path = env::current_dir()?;
@@ -233,18 +1181,1080 @@ fn main() -> Result<()> {
         bail!("{} is not a valid directory", directory)
     }
 
+    let chunked = matches.is_present("chunked");
+
     if matches.is_present("sync") {
-        let out_writer = match matches.value_of("output") {
-            Some(output) => {
-                let path = Path::new(output);
-                Box::new(File::create(&path)?) as Box<dyn Write>
-            }
-            None => Box::new(std::io::stdout()) as Box<dyn Write>,
-        };
-        sync_directory(directory, state_file_path, out_writer)?;
+        let apply = matches.is_present("apply");
+        let watching = matches.is_present("watch");
+        let reference_directory = matches.value_of("reference-directory");
+        if apply && reference_directory.is_none() {
+            bail!("--apply requires --reference-directory to know where to copy files from");
+        }
+        if watching && reference_directory.is_none() {
+            bail!("--watch requires --reference-directory to know what to watch");
+        }
+
+        let fs: Box<dyn Fs> = Box::new(RealFs);
+        let output = matches.value_of("output");
+
+        if watching {
+            run_watch(
+                directory,
+                reference_directory.unwrap(),
+                state_file_path,
+                chunked,
+                apply,
+                output,
+                fs.as_ref(),
+            )?;
+        } else {
+            run_plan_and_act(
+                directory,
+                state_file_path,
+                reference_directory,
+                chunked,
+                apply,
+                output,
+                fs.as_ref(),
+                None,
+            )?;
+        }
     } else {
-        save_state(directory, state_file_path)?;
+        save_state(directory, state_file_path, chunked)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vfs::FakeFs;
+
+    #[test]
+    fn plan_orders_creates_before_deletes_and_parents_before_children() {
+        let mut plan = [
+            Operation::DeleteFile(PathBuf::from("target/old/leftover.txt")),
+            Operation::DeleteDir(PathBuf::from("target/old")),
+            Operation::Create(PathBuf::from("target/new")),
+            Operation::Copy {
+                from: PathBuf::from("reference/new/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            },
+        ];
+        plan.sort_by_key(|op| match op {
+            Operation::Create(_)
+            | Operation::Copy { .. }
+            | Operation::Move { .. }
+            | Operation::CopyChunks { .. }
+            | Operation::Symlink { .. }
+            | Operation::Retarget { .. }
+            | Operation::Chmod { .. } => 0,
+            Operation::DeleteFile(_) | Operation::DeleteDir(_) => 1,
+        });
+
+        let creates_and_copies: Vec<_> = plan
+            .iter()
+            .take_while(|op| {
+                matches!(
+                    op,
+                    Operation::Create(_)
+                        | Operation::Copy { .. }
+                        | Operation::Move { .. }
+                        | Operation::CopyChunks { .. }
+                )
+            })
+            .collect();
+        assert_eq!(creates_and_copies.len(), 2);
+
+        assert_eq!(
+            plan[0].target_path().components().count(),
+            2,
+            "the directory must be created before the file inside it is copied"
+        );
+    }
+
+    #[test]
+    fn apply_plan_executes_operations_through_the_fs_trait() {
+        let fs = FakeFs::with_files(vec![(
+            PathBuf::from("reference/file.txt"),
+            b"hello".to_vec(),
+        )]);
+        fs.create_dir(Path::new("target/old")).unwrap();
+        fs.copy_file(
+            Path::new("reference/file.txt"),
+            Path::new("target/old/leftover.txt"),
+        )
+        .unwrap();
+
+        let plan = vec![
+            Operation::Create(PathBuf::from("target/new")),
+            Operation::Copy {
+                from: PathBuf::from("reference/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            },
+            Operation::DeleteFile(PathBuf::from("target/old/leftover.txt")),
+            Operation::DeleteDir(PathBuf::from("target/old")),
+        ];
+
+        apply_plan(&fs, &plan).unwrap();
+
+        assert!(fs.exists(Path::new("target/new")));
+        assert_eq!(
+            fs.read_file(Path::new("target/new/file.txt")),
+            Some(b"hello".to_vec())
+        );
+        assert!(!fs.exists(Path::new("target/old/leftover.txt")));
+        assert!(!fs.exists(Path::new("target/old")));
+    }
+
+    #[test]
+    fn resolve_renames_moves_instead_of_copying_when_content_already_exists_in_target() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/new/file.txt"),
+            relative_self: PathBuf::from("new/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o644,
+        }];
+        let pending_deletes = vec![PendingDelete {
+            path: PathBuf::from("target/old/file.txt"),
+            is_dir: false,
+            hash: Some("HASH".to_string()),
+            mode: 0o644,
+        }];
+
+        let (moves, copies, deletes) = resolve_renames(pending_copies, pending_deletes, None, &[]);
+
+        assert_eq!(
+            moves,
+            vec![Operation::Move {
+                from: PathBuf::from("target/old/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            }]
+        );
+        assert!(copies.is_empty());
+        assert!(
+            deletes.is_empty(),
+            "the rename source must not also be deleted"
+        );
+    }
+
+    #[test]
+    fn resolve_renames_never_matches_a_kind_mismatch_delete() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/new/file.txt"),
+            relative_self: PathBuf::from("new/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o644,
+        }];
+        let pending_deletes = vec![PendingDelete {
+            path: PathBuf::from("target/data"),
+            is_dir: false,
+            hash: Some("HASH".to_string()),
+            mode: 0o644,
+        }];
+        let kind_mismatch_paths = vec![PathBuf::from("target/data")];
+
+        let (moves, copies, deletes) = resolve_renames(
+            pending_copies,
+            pending_deletes,
+            None,
+            &kind_mismatch_paths,
+        );
+
+        assert!(
+            moves.is_empty(),
+            "a kind-mismatch delete must not be offered as a rename source"
+        );
+        assert_eq!(
+            copies,
+            vec![Operation::Copy {
+                from: PathBuf::from("new/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            }]
+        );
+        assert_eq!(deletes, vec![Operation::DeleteFile(PathBuf::from("target/data"))]);
+    }
+
+    #[test]
+    fn resolve_renames_never_matches_a_delete_nested_under_a_kind_mismatch_path() {
+        // reference/data is a file; target/data is a directory containing
+        // leaf.txt with the same content the reference file has, so
+        // target/data/leaf.txt would otherwise look like a perfect rename
+        // source for the incoming target/data copy
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/data"),
+            relative_self: PathBuf::from("data"),
+            hash: "SAME".to_string(),
+            mode: 0o644,
+        }];
+        let pending_deletes = vec![PendingDelete {
+            path: PathBuf::from("target/data/leaf.txt"),
+            is_dir: false,
+            hash: Some("SAME".to_string()),
+            mode: 0o644,
+        }];
+        let kind_mismatch_paths = vec![PathBuf::from("target/data")];
+
+        let (moves, copies, deletes) = resolve_renames(
+            pending_copies,
+            pending_deletes,
+            None,
+            &kind_mismatch_paths,
+        );
+
+        assert!(
+            moves.is_empty(),
+            "a delete nested under a kind-mismatch path must not be a rename source, \
+             or the directory delete that must run before it would lose its child"
+        );
+        assert_eq!(
+            copies,
+            vec![Operation::Copy {
+                from: PathBuf::from("data"),
+                to: PathBuf::from("target/data"),
+            }]
+        );
+        assert_eq!(
+            deletes,
+            vec![Operation::DeleteFile(PathBuf::from("target/data/leaf.txt"))]
+        );
+    }
+
+    #[test]
+    fn resolve_renames_picks_the_closest_candidate_among_several_equal_hashes() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/a/b/file.txt"),
+            relative_self: PathBuf::from("a/b/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o644,
+        }];
+        let pending_deletes = vec![
+            PendingDelete {
+                path: PathBuf::from("target/far/away/file.txt"),
+                is_dir: false,
+                hash: Some("HASH".to_string()),
+                mode: 0o644,
+            },
+            PendingDelete {
+                path: PathBuf::from("target/a/file.txt"),
+                is_dir: false,
+                hash: Some("HASH".to_string()),
+                mode: 0o644,
+            },
+        ];
+
+        let (moves, _, _) = resolve_renames(pending_copies, pending_deletes, None, &[]);
+
+        assert_eq!(
+            moves,
+            vec![Operation::Move {
+                from: PathBuf::from("target/a/file.txt"),
+                to: PathBuf::from("target/a/b/file.txt"),
+            }]
+        );
+    }
+
+    #[test]
+    fn resolve_renames_breaks_an_equal_prefix_tie_on_path_not_insertion_order() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/new/file.txt"),
+            relative_self: PathBuf::from("new/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o644,
+        }];
+        // both candidates share zero path components with the destination's
+        // parent, so `shared_prefix_len` alone can't break the tie; the
+        // choice must still be stable across runs rather than depend on
+        // `pending_deletes`'s order, which comes from hashing a `HashMap`
+        let pending_deletes = vec![
+            PendingDelete {
+                path: PathBuf::from("target/zzz/file.txt"),
+                is_dir: false,
+                hash: Some("HASH".to_string()),
+                mode: 0o644,
+            },
+            PendingDelete {
+                path: PathBuf::from("target/aaa/file.txt"),
+                is_dir: false,
+                hash: Some("HASH".to_string()),
+                mode: 0o644,
+            },
+        ];
+
+        let (moves, _, _) = resolve_renames(pending_copies, pending_deletes, None, &[]);
+
+        assert_eq!(
+            moves,
+            vec![Operation::Move {
+                from: PathBuf::from("target/aaa/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            }]
+        );
+    }
+
+    #[test]
+    fn resolve_renames_chmods_a_moved_file_whose_mode_differs_from_the_reference() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/new/file.txt"),
+            relative_self: PathBuf::from("new/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o600,
+        }];
+        let pending_deletes = vec![PendingDelete {
+            path: PathBuf::from("target/old/file.txt"),
+            is_dir: false,
+            hash: Some("HASH".to_string()),
+            mode: 0o644,
+        }];
+
+        let (moves, _, _) = resolve_renames(pending_copies, pending_deletes, None, &[]);
+
+        assert_eq!(
+            moves,
+            vec![
+                Operation::Move {
+                    from: PathBuf::from("target/old/file.txt"),
+                    to: PathBuf::from("target/new/file.txt"),
+                },
+                Operation::Chmod {
+                    path: PathBuf::from("target/new/file.txt"),
+                    mode: 0o600,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_renames_skips_chmod_when_the_moved_files_mode_already_matches() {
+        let pending_copies = vec![PendingCopy {
+            to: PathBuf::from("target/new/file.txt"),
+            relative_self: PathBuf::from("new/file.txt"),
+            hash: "HASH".to_string(),
+            mode: 0o644,
+        }];
+        let pending_deletes = vec![PendingDelete {
+            path: PathBuf::from("target/old/file.txt"),
+            is_dir: false,
+            hash: Some("HASH".to_string()),
+            mode: 0o644,
+        }];
+
+        let (moves, _, _) = resolve_renames(pending_copies, pending_deletes, None, &[]);
+
+        assert_eq!(
+            moves,
+            vec![Operation::Move {
+                from: PathBuf::from("target/old/file.txt"),
+                to: PathBuf::from("target/new/file.txt"),
+            }]
+        );
+    }
+
+    fn chunk(offset: u64, len: u32, hash: &str) -> ChunkRecord {
+        ChunkRecord {
+            offset,
+            len,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn diff_chunks_only_reports_ranges_that_changed() {
+        let reference = vec![chunk(0, 10, "A"), chunk(10, 10, "B"), chunk(20, 10, "C")];
+        let target = vec![chunk(0, 10, "A"), chunk(10, 10, "CHANGED"), chunk(20, 10, "C")];
+
+        let ranges = diff_chunks(&reference, &target);
+
+        assert_eq!(
+            ranges,
+            vec![ChunkRange {
+                offset: 10,
+                len: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn diff_chunks_merges_adjacent_changed_ranges() {
+        let reference = vec![chunk(0, 10, "A"), chunk(10, 10, "B")];
+        let target = vec![chunk(0, 10, "CHANGED-A"), chunk(10, 10, "CHANGED-B")];
+
+        let ranges = diff_chunks(&reference, &target);
+
+        assert_eq!(ranges, vec![ChunkRange { offset: 0, len: 20 }]);
+    }
+
+    #[test]
+    fn chunk_diff_is_none_unless_both_sides_were_chunked() {
+        let chunked = Record {
+            name: "file.txt".to_string(),
+            hash: Some("H".to_string()),
+            size: 10,
+            mtime: 0,
+            chunks: Some(vec![chunk(0, 10, "A")]),
+            kind: EntryKind::File,
+            mode: 0o644,
+        };
+        let not_chunked = Record {
+            name: "file.txt".to_string(),
+            hash: Some("H2".to_string()),
+            size: 10,
+            mtime: 0,
+            chunks: None,
+            kind: EntryKind::File,
+            mode: 0o644,
+        };
+
+        assert!(chunk_diff(&chunked, &not_chunked).is_none());
+        assert!(chunk_diff(&chunked, &chunked).is_some());
+    }
+
+    #[test]
+    fn apply_plan_patches_only_the_changed_chunk_of_a_file() {
+        let fs = FakeFs::with_files(vec![
+            (PathBuf::from("reference/file.txt"), b"AAAABBBB".to_vec()),
+            (PathBuf::from("target/file.txt"), b"AAAAXXXX".to_vec()),
+        ]);
+
+        let plan = vec![Operation::CopyChunks {
+            from: PathBuf::from("reference/file.txt"),
+            to: PathBuf::from("target/file.txt"),
+            ranges: vec![ChunkRange { offset: 4, len: 4 }],
+        }];
+
+        apply_plan(&fs, &plan).unwrap();
+
+        assert_eq!(
+            fs.read_file(Path::new("target/file.txt")),
+            Some(b"AAAABBBB".to_vec())
+        );
+    }
+
+    fn watch_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn apply_watch_paths_adds_a_newly_created_file() {
+        let dir = watch_test_dir("watch_test_add_file");
+        fs::write(dir.join("new.txt"), b"hi").unwrap();
+
+        let mut entries: Entries = HashMap::new();
+        entries.insert(String::new(), Vec::new());
+
+        apply_watch_paths(
+            &mut entries,
+            dir.to_str().unwrap(),
+            &[dir.join("new.txt")],
+            &IgnoreMatcher::empty(),
+            false,
+        )
+        .unwrap();
+
+        assert!(entries[""].iter().any(|record| record.name == "new.txt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_watch_paths_walks_a_newly_created_directorys_existing_contents() {
+        let dir = watch_test_dir("watch_test_add_nonempty_dir");
+        // simulate a directory that appeared via a single atomic event (a
+        // rename into the watched tree, say) already containing files; a
+        // batch for this would report only `dir/newdir` itself, never its
+        // pre-existing children
+        fs::create_dir_all(dir.join("newdir").join("nested")).unwrap();
+        fs::write(dir.join("newdir").join("child.txt"), b"hi").unwrap();
+        fs::write(dir.join("newdir").join("nested").join("grandchild.txt"), b"ho").unwrap();
+
+        let mut entries: Entries = HashMap::new();
+        entries.insert(String::new(), Vec::new());
+
+        apply_watch_paths(
+            &mut entries,
+            dir.to_str().unwrap(),
+            &[dir.join("newdir")],
+            &IgnoreMatcher::empty(),
+            false,
+        )
+        .unwrap();
+
+        assert!(entries[""].iter().any(|record| record.name == "newdir"));
+        assert!(
+            entries["newdir"].iter().any(|record| record.name == "child.txt"),
+            "a file that already existed inside the new directory must be captured"
+        );
+        assert!(
+            entries["newdir"].iter().any(|record| record.name == "nested"),
+            "a subdirectory that already existed inside the new directory must be captured"
+        );
+        assert!(
+            entries["newdir/nested"]
+                .iter()
+                .any(|record| record.name == "grandchild.txt"),
+            "files nested more than one level deep must be captured too"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_watch_paths_collects_an_unreadable_file_as_a_bad_path_instead_of_aborting() {
+        if running_as_root() {
+            return;
+        }
+
+        let dir = watch_test_dir("watch_test_unreadable_file");
+        fs::write(dir.join("bad.txt"), b"nope").unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir.join("bad.txt"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut entries: Entries = HashMap::new();
+        entries.insert(String::new(), Vec::new());
+
+        // must return Ok and continue, not propagate the permission error
+        // and kill the whole `--watch` process
+        apply_watch_paths(
+            &mut entries,
+            dir.to_str().unwrap(),
+            &[dir.join("bad.txt")],
+            &IgnoreMatcher::empty(),
+            false,
+        )
+        .unwrap();
+
+        assert!(!entries[""].iter().any(|record| record.name == "bad.txt"));
+
+        fs::set_permissions(dir.join("bad.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_watch_paths_removes_a_deleted_file() {
+        let dir = watch_test_dir("watch_test_remove_file");
+
+        let mut entries: Entries = HashMap::new();
+        entries.insert(
+            String::new(),
+            vec![Record {
+                name: "gone.txt".to_string(),
+                hash: Some("H".to_string()),
+                size: 2,
+                mtime: 0,
+                chunks: None,
+                kind: EntryKind::File,
+                mode: 0o644,
+            }],
+        );
+
+        apply_watch_paths(
+            &mut entries,
+            dir.to_str().unwrap(),
+            &[dir.join("gone.txt")],
+            &IgnoreMatcher::empty(),
+            false,
+        )
+        .unwrap();
+
+        assert!(entries[""].is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_watch_paths_removing_a_directory_purges_its_subtree() {
+        let dir = watch_test_dir("watch_test_remove_dir");
+
+        let mut entries: Entries = HashMap::new();
+        entries.insert(
+            String::new(),
+            vec![Record {
+                name: "sub".to_string(),
+                hash: None,
+                size: 0,
+                mtime: 0,
+                chunks: None,
+                kind: EntryKind::Dir,
+                mode: 0o755,
+            }],
+        );
+        entries.insert(
+            "sub".to_string(),
+            vec![Record {
+                name: "leaf.txt".to_string(),
+                hash: Some("H".to_string()),
+                size: 1,
+                mtime: 0,
+                chunks: None,
+                kind: EntryKind::File,
+                mode: 0o644,
+            }],
+        );
+
+        apply_watch_paths(
+            &mut entries,
+            dir.to_str().unwrap(),
+            &[dir.join("sub")],
+            &IgnoreMatcher::empty(),
+            false,
+        )
+        .unwrap();
+
+        assert!(entries[""].is_empty());
+        assert!(
+            !entries.contains_key("sub"),
+            "the removed directory's subtree entry must be dropped too"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn same_kind_ignores_a_symlinks_target() {
+        assert!(same_kind(
+            &EntryKind::Symlink { target: PathBuf::from("a") },
+            &EntryKind::Symlink { target: PathBuf::from("b") },
+        ));
+        assert!(!same_kind(&EntryKind::Dir, &EntryKind::File));
+    }
+
+    #[test]
+    fn build_symlink_record_captures_target_and_mode_without_following_it() {
+        let dir = watch_test_dir("build_symlink_record_test");
+        let link = dir.join("link");
+        std::os::unix::fs::symlink("/does/not/exist", &link).unwrap();
+
+        let record = build_symlink_record("link", &link).unwrap();
+
+        assert_eq!(record.kind, EntryKind::Symlink { target: PathBuf::from("/does/not/exist") });
+        assert!(record.hash.is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn map_directory_classifies_a_symlink_without_following_it() {
+        let dir = watch_test_dir("map_directory_symlink_test");
+        std::os::unix::fs::symlink("/does/not/exist", dir.join("link")).unwrap();
+
+        let (entries, bad_paths) =
+            map_directory(dir.to_str().unwrap(), None, &IgnoreMatcher::empty(), false).unwrap();
+
+        assert!(bad_paths.is_empty());
+        let record = entries[""].iter().find(|record| record.name == "link").unwrap();
+        assert_eq!(record.kind, EntryKind::Symlink { target: PathBuf::from("/does/not/exist") });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // root ignores POSIX permission bits, so a test that relies on
+    // `0o000` being unreadable would be flaky under a root test runner
+    fn running_as_root() -> bool {
+        extern "C" {
+            fn geteuid() -> u32;
+        }
+        unsafe { geteuid() == 0 }
+    }
+
+    #[test]
+    fn map_directory_collects_an_unreadable_file_as_a_bad_path_instead_of_aborting() {
+        if running_as_root() {
+            return;
+        }
+
+        let dir = watch_test_dir("map_directory_unreadable_file_test");
+        fs::write(dir.join("good.txt"), b"ok").unwrap();
+        fs::write(dir.join("bad.txt"), b"nope").unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir.join("bad.txt"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        let (entries, bad_paths) =
+            map_directory(dir.to_str().unwrap(), None, &IgnoreMatcher::empty(), false).unwrap();
+
+        assert_eq!(bad_paths.len(), 1);
+        assert_eq!(bad_paths[0].path, dir.join("bad.txt"));
+        assert!(
+            entries[""].iter().any(|record| record.name == "good.txt"),
+            "a sibling file must still be recorded even though bad.txt couldn't be read"
+        );
+        assert!(!entries[""].iter().any(|record| record.name == "bad.txt"));
+
+        fs::set_permissions(dir.join("bad.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_plan_creates_and_retargets_symlinks_through_the_fs_trait() {
+        let fs = FakeFs::new();
+        fs.create_dir(Path::new("target")).unwrap();
+
+        apply_plan(
+            &fs,
+            &[Operation::Symlink {
+                path: PathBuf::from("target/link"),
+                target: PathBuf::from("a"),
+            }],
+        )
+        .unwrap();
+        assert_eq!(fs.read_symlink(Path::new("target/link")), Some(PathBuf::from("a")));
+
+        apply_plan(
+            &fs,
+            &[Operation::Retarget {
+                path: PathBuf::from("target/link"),
+                target: PathBuf::from("b"),
+            }],
+        )
+        .unwrap();
+        assert_eq!(fs.read_symlink(Path::new("target/link")), Some(PathBuf::from("b")));
+    }
+
+    #[test]
+    fn plan_sync_chmods_a_file_whose_permissions_changed() {
+        let dir = watch_test_dir("plan_sync_chmod_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(&reference_dir).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(reference_dir.join("file.txt"), b"hello").unwrap();
+        fs::write(target_dir.join("file.txt"), b"hello").unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = fs::metadata(reference_dir.join("file.txt")).unwrap().permissions();
+        permissions.set_mode(0o600);
+        fs::set_permissions(reference_dir.join("file.txt"), permissions).unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let fake_fs = FakeFs::new();
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &fake_fs,
+            None,
+        )
+        .unwrap();
+
+        assert!(plan.iter().any(|op| matches!(
+            op,
+            Operation::Chmod { mode, .. } if *mode == 0o600
+        )));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plan_sync_recreates_a_directory_that_a_file_is_occupying_in_target() {
+        let dir = watch_test_dir("plan_sync_kind_mismatch_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(reference_dir.join("data")).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(reference_dir.join("data").join("child.txt"), b"hello").unwrap();
+        // target has a plain file where the reference has a directory
+        fs::write(target_dir.join("data"), b"not a directory").unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        // mark `target/data` as already present so the plan's kind-mismatch
+        // handling, not its separate "entirely missing from target" handling,
+        // is what's under test
+        let fake_fs = FakeFs::new();
+        fake_fs.create_dir(&target_dir.join("data")).unwrap();
+
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &fake_fs,
+            None,
+        )
+        .unwrap();
+
+        assert!(
+            plan.iter()
+                .any(|op| matches!(op, Operation::DeleteFile(path) if path == &target_dir.join("data"))),
+            "the mismatched file must still be deleted"
+        );
+        assert!(
+            plan.iter()
+                .any(|op| matches!(op, Operation::Create(path) if path == &target_dir.join("data"))),
+            "the reference directory must be recreated, not just deleted"
+        );
+        assert!(
+            plan.iter().any(|op| matches!(
+                op,
+                Operation::Copy { to, .. } if to == &target_dir.join("data").join("child.txt")
+            )),
+            "the recreated directory's contents must be copied in too"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plan_sync_does_not_rename_match_a_kind_mismatch_delete_with_a_same_hash_copy() {
+        let dir = watch_test_dir("plan_sync_kind_mismatch_rename_race_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(reference_dir.join("data")).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        // an empty file the reference wants placed somewhere new...
+        fs::write(reference_dir.join("data").join("child.txt"), b"").unwrap();
+        // ...and target has an empty file sitting where the reference wants
+        // a directory, sharing that same (empty-content) hash, so it would
+        // be a rename-match candidate if kind-mismatch deletes were eligible
+        fs::write(target_dir.join("data"), b"").unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let fake_fs = FakeFs::new();
+        fake_fs.create_dir(&target_dir.join("data")).unwrap();
+
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &fake_fs,
+            None,
+        )
+        .unwrap();
+
+        let mismatch_delete_index = plan
+            .iter()
+            .position(|op| matches!(op, Operation::DeleteFile(path) if path == &target_dir.join("data")))
+            .expect("the mismatched file must still be an ordinary delete, not a rename source");
+        let recreate_index = plan
+            .iter()
+            .position(|op| matches!(op, Operation::Create(path) if path == &target_dir.join("data")))
+            .expect("the reference directory must be recreated");
+        assert!(
+            mismatch_delete_index < recreate_index,
+            "the stale file must be gone before its replacement directory is created"
+        );
+        assert!(
+            !plan.iter().any(|op| matches!(op, Operation::Move { from, .. } if from == &target_dir.join("data"))),
+            "a kind-mismatch path must never be used as a rename source"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_plan_recreates_a_file_that_a_same_hash_child_would_otherwise_rename_into() {
+        let dir = watch_test_dir("apply_plan_kind_mismatch_rename_race_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(&reference_dir).unwrap();
+        fs::create_dir_all(target_dir.join("data")).unwrap();
+        // reference wants a plain file at `data`...
+        fs::write(reference_dir.join("data"), b"SAME").unwrap();
+        // ...but target has a directory there, containing a child with that
+        // exact content; without also excluding deletes nested under a
+        // kind-mismatch path, leaf.txt gets rename-matched into `data`
+        // instead of deleted, so `data`'s directory delete runs while it's
+        // still non-empty
+        fs::write(target_dir.join("data").join("leaf.txt"), b"SAME").unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let real_fs = RealFs;
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &real_fs,
+            None,
+        )
+        .unwrap();
+
+        apply_plan(&real_fs, &plan).unwrap();
+
+        assert!(target_dir.join("data").is_file());
+        assert_eq!(fs::read(target_dir.join("data")).unwrap(), b"SAME");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_plan_recreates_a_directory_that_a_file_is_occupying_in_target() {
+        let dir = watch_test_dir("apply_plan_kind_mismatch_dir_over_file_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(reference_dir.join("data")).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(reference_dir.join("data").join("child.txt"), b"hello").unwrap();
+        // target has a plain file where the reference has a directory
+        fs::write(target_dir.join("data"), b"not a directory").unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let real_fs = RealFs;
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &real_fs,
+            None,
+        )
+        .unwrap();
+
+        apply_plan(&real_fs, &plan).unwrap();
+
+        assert!(target_dir.join("data").is_dir());
+        assert_eq!(
+            fs::read(target_dir.join("data").join("child.txt")).unwrap(),
+            b"hello"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_plan_recreates_a_file_that_a_directory_is_occupying_in_target() {
+        let dir = watch_test_dir("apply_plan_kind_mismatch_file_over_dir_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(&reference_dir).unwrap();
+        fs::write(reference_dir.join("data"), b"hello").unwrap();
+        // target has a (non-empty) directory where the reference has a file
+        fs::create_dir_all(target_dir.join("data")).unwrap();
+        fs::write(target_dir.join("data").join("leftover.txt"), b"old").unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let real_fs = RealFs;
+        let (plan, _) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &real_fs,
+            None,
+        )
+        .unwrap();
+
+        apply_plan(&real_fs, &plan).unwrap();
+
+        assert!(target_dir.join("data").is_file());
+        assert_eq!(fs::read(target_dir.join("data")).unwrap(), b"hello");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plan_sync_reuses_a_cached_target_hash_when_previous_target_is_passed() {
+        let dir = watch_test_dir("plan_sync_target_cache_test");
+        let reference_dir = dir.join("reference");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(&reference_dir).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(reference_dir.join("file.txt"), b"hello").unwrap();
+        fs::write(target_dir.join("file.txt"), b"hello").unwrap();
+
+        // back-date the target file's mtime so it falls outside `is_racy`'s
+        // same-second window; otherwise the cache would be (correctly)
+        // distrusted and this test couldn't tell a cache hit from a rehash
+        let backdated = filetime::FileTime::from_system_time(
+            SystemTime::now() - Duration::from_secs(10),
+        );
+        filetime::set_file_mtime(target_dir.join("file.txt"), backdated).unwrap();
+
+        let state_path = dir.join("state");
+        save_state(
+            reference_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let fake_fs = FakeFs::new();
+        let (_, target_entries) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &fake_fs,
+            None,
+        )
+        .unwrap();
+
+        // poison the cached hash without touching size/mtime, so a second
+        // call can only have picked it up by trusting the cache rather than
+        // re-hashing `file.txt`'s unchanged content
+        let mut stale_target_entries = target_entries;
+        for record in stale_target_entries.get_mut("").unwrap() {
+            if record.name == "file.txt" {
+                record.hash = Some("STALE".to_string());
+            }
+        }
+
+        let (plan, target_entries) = plan_sync(
+            target_dir.to_str().unwrap(),
+            state_path.to_str().unwrap(),
+            Some(reference_dir.to_str().unwrap()),
+            false,
+            &fake_fs,
+            Some(&stale_target_entries),
+        )
+        .unwrap();
+
+        let record = target_entries[""].iter().find(|record| record.name == "file.txt").unwrap();
+        assert_eq!(record.hash.as_deref(), Some("STALE"));
+        // and the planner, trusting that stale hash, thinks the file differs
+        // from the reference and schedules a copy to fix it back up
+        assert!(plan
+            .iter()
+            .any(|op| matches!(op, Operation::Copy { to, .. } if to == &target_dir.join("file.txt"))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
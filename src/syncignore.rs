@@ -0,0 +1,193 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// a single compiled `.syncignore` rule
+struct Pattern {
+    glob: glob::Pattern,
+    negated: bool,
+}
+
+/// Compiled `.syncignore` rules, consulted by `map_directory` before it
+/// recurses so excluded paths never reach the hash/state-capture stage.
+///
+/// Patterns follow gitignore conventions: a leading `!` negates a rule, and a
+/// leading `/` anchors it to the directory the `.syncignore` file lives in
+/// (an unanchored pattern matches at any depth). `%include <path>` pulls in
+/// another pattern file relative to the file containing the directive.
+pub struct IgnoreMatcher {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreMatcher {
+    /// A matcher that excludes nothing, used when there is no `.syncignore`.
+    pub fn empty() -> Self {
+        IgnoreMatcher {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Load `<directory>/.syncignore`, if present, resolving `%include`
+    /// directives relative to the file that contains them.
+    pub fn load_for_directory(directory: &str) -> Result<Self> {
+        let path = Path::new(directory).join(".syncignore");
+        if !path.exists() {
+            return Ok(Self::empty());
+        }
+
+        let mut patterns = Vec::new();
+        let mut visited = HashSet::new();
+        load_file(&path, &mut patterns, &mut visited)?;
+        Ok(IgnoreMatcher { patterns })
+    }
+
+    /// Whether `relative_path` (relative to the directory being walked)
+    /// should be excluded. The last matching pattern wins, so a later `!`
+    /// rule can un-ignore a path an earlier rule excluded.
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        let candidate = to_slash(relative_path);
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.glob.matches_with(&candidate, MATCH_OPTIONS) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+// `*`/`?` must never cross a `/`, or an anchored pattern like `/*.log` would
+// also match `nested/debug.log`; depth-crossing is only ever intentional via
+// the explicit `**/` prefix `compile_pattern` adds to unanchored patterns.
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+fn load_file(path: &Path, patterns: &mut Vec<Pattern>, visited: &mut HashSet<PathBuf>) -> Result<()> {
+    let canonical = fs::canonicalize(path)
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    if !visited.insert(canonical) {
+        bail!(
+            "%include cycle detected while loading {}",
+            path.display()
+        );
+    }
+
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(include) = line.strip_prefix("%include ") {
+            load_file(&directory.join(include.trim()), patterns, visited)?;
+            continue;
+        }
+
+        let pattern = compile_pattern(line)
+            .with_context(|| format!("Invalid pattern `{}` in {}", line, path.display()))?;
+        patterns.push(pattern);
+    }
+
+    Ok(())
+}
+
+fn compile_pattern(line: &str) -> Result<Pattern> {
+    let (negated, rest) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+
+    // an anchored pattern only matches from the `.syncignore`'s own
+    // directory; an unanchored one matches at any depth, same as gitignore
+    let spec = match rest.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None => format!("**/{}", rest),
+    };
+
+    Ok(Pattern {
+        glob: glob::Pattern::new(&spec)?,
+        negated,
+    })
+}
+
+fn to_slash(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(lines: &[&str]) -> IgnoreMatcher {
+        let mut patterns = Vec::new();
+        for line in lines {
+            patterns.push(compile_pattern(line).unwrap());
+        }
+        IgnoreMatcher { patterns }
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_at_any_depth() {
+        let matcher = matcher(&["*.log"]);
+        assert!(matcher.is_ignored(Path::new("debug.log")));
+        assert!(matcher.is_ignored(Path::new("nested/deep/debug.log")));
+        assert!(!matcher.is_ignored(Path::new("debug.log.bak")));
+    }
+
+    #[test]
+    fn anchored_pattern_only_matches_from_the_syncignore_directory() {
+        let matcher = matcher(&["/build"]);
+        assert!(matcher.is_ignored(Path::new("build")));
+        assert!(!matcher.is_ignored(Path::new("nested/build")));
+    }
+
+    #[test]
+    fn anchored_pattern_with_a_wildcard_does_not_cross_directories() {
+        let matcher = matcher(&["/*.log"]);
+        assert!(matcher.is_ignored(Path::new("debug.log")));
+        assert!(!matcher.is_ignored(Path::new("nested/debug.log")));
+    }
+
+    #[test]
+    fn later_negation_overrides_an_earlier_ignore() {
+        let matcher = matcher(&["*.log", "!keep.log"]);
+        assert!(matcher.is_ignored(Path::new("debug.log")));
+        assert!(!matcher.is_ignored(Path::new("keep.log")));
+    }
+
+    #[test]
+    fn include_directive_pulls_in_patterns_from_another_file() {
+        let dir = std::env::temp_dir().join("syncignore_test_include");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("shared"), "*.tmp\n").unwrap();
+        fs::write(dir.join(".syncignore"), "%include shared\n*.log\n").unwrap();
+
+        let matcher = IgnoreMatcher::load_for_directory(dir.to_str().unwrap()).unwrap();
+        assert!(matcher.is_ignored(Path::new("scratch.tmp")));
+        assert!(matcher.is_ignored(Path::new("debug.log")));
+        assert!(!matcher.is_ignored(Path::new("keep.txt")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle_is_rejected() {
+        let dir = std::env::temp_dir().join("syncignore_test_cycle");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".syncignore"), "%include back\n").unwrap();
+        fs::write(dir.join("back"), "%include .syncignore\n").unwrap();
+
+        let result = IgnoreMatcher::load_for_directory(dir.to_str().unwrap());
+        assert!(result.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Minimal file metadata, enough for the sync planner to reason about a path
+/// without depending on `std::fs::Metadata` (which `FakeFs` can't construct).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+}
+
+/// A byte range within a file, as produced by a chunk-level diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRange {
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// Filesystem operations needed to execute a sync plan, modeled on Zed's
+/// `project::fs::Fs` so the plan can be applied for real or against an
+/// in-memory double in tests.
+pub trait Fs: Send + Sync {
+    fn create_dir(&self, path: &Path) -> Result<()>;
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    fn remove_file(&self, path: &Path) -> Result<()>;
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+    fn metadata(&self, path: &Path) -> Result<Option<Metadata>>;
+    /// Patch `to` so the given byte ranges (read from `from`) match, then
+    /// truncate or extend `to` to `from`'s length. Ranges outside those
+    /// given are assumed to already agree between `from` and `to`.
+    fn copy_chunks(&self, from: &Path, to: &Path, ranges: &[ChunkRange]) -> Result<()>;
+    /// Create a symlink at `path` pointing at `target`, without following or
+    /// validating `target` (it may be relative, or dangling).
+    fn create_symlink(&self, path: &Path, target: &Path) -> Result<()>;
+    /// Set `path`'s permission bits to `mode` (the low 12 bits, as returned
+    /// by `MetadataExt::mode`).
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()>;
+}
+
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create directory {}", path.display()))
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        std::fs::copy(from, to)
+            .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        std::fs::rename(from, to)
+            .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove file {}", path.display()))
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        std::fs::remove_dir(path)
+            .with_context(|| format!("Failed to remove directory {}", path.display()))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<Metadata>> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(Some(Metadata {
+                is_dir: metadata.is_dir(),
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to stat {}", path.display())),
+        }
+    }
+
+    fn copy_chunks(&self, from: &Path, to: &Path, ranges: &[ChunkRange]) -> Result<()> {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom};
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+
+        let mut source = File::open(from)
+            .with_context(|| format!("Failed to open {}", from.display()))?;
+        let mut destination = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(to)
+            .with_context(|| format!("Failed to open {}", to.display()))?;
+
+        let mut buffer = Vec::new();
+        for range in ranges {
+            buffer.resize(range.len as usize, 0);
+            source.seek(SeekFrom::Start(range.offset))?;
+            source.read_exact(&mut buffer)?;
+            destination.seek(SeekFrom::Start(range.offset))?;
+            destination.write_all(&buffer)?;
+        }
+
+        let total_len = source.seek(SeekFrom::End(0))?;
+        destination.set_len(total_len)?;
+
+        Ok(())
+    }
+
+    fn create_symlink(&self, path: &Path, target: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        std::os::unix::fs::symlink(target, path).with_context(|| {
+            format!("Failed to create symlink {} -> {}", path.display(), target.display())
+        })
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions on {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+enum FakeEntry {
+    Dir,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+}
+
+/// An in-memory `Fs` double for tests, keyed by path so operation ordering
+/// (creates/copies before deletes, parents before children) can be asserted
+/// without touching the real filesystem.
+#[cfg(test)]
+pub struct FakeFs {
+    entries: Mutex<HashMap<PathBuf, FakeEntry>>,
+}
+
+#[cfg(test)]
+impl FakeFs {
+    pub fn new() -> Self {
+        FakeFs {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_files<I>(files: I) -> Self
+    where
+        I: IntoIterator<Item = (PathBuf, Vec<u8>)>,
+    {
+        let fs = FakeFs::new();
+        for (path, contents) in files {
+            if let Some(parent) = path.parent() {
+                fs.entries
+                    .lock()
+                    .unwrap()
+                    .insert(parent.to_path_buf(), FakeEntry::Dir);
+            }
+            fs.entries.lock().unwrap().insert(path, FakeEntry::File(contents));
+        }
+        fs
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        self.entries.lock().unwrap().contains_key(path)
+    }
+
+    pub fn read_file(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(FakeEntry::File(contents)) => Some(contents.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn read_symlink(&self, path: &Path) -> Option<PathBuf> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(FakeEntry::Symlink(target)) => Some(target.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for FakeFs {
+    fn default() -> Self {
+        FakeFs::new()
+    }
+}
+
+#[cfg(test)]
+impl Fs for FakeFs {
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), FakeEntry::Dir);
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let contents = match self.entries.lock().unwrap().get(from) {
+            Some(FakeEntry::File(contents)) => contents.clone(),
+            _ => bail_not_found(from)?,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(to.to_path_buf(), FakeEntry::File(contents));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.remove(from) {
+            Some(entry) => {
+                entries.insert(to.to_path_buf(), entry);
+                Ok(())
+            }
+            None => bail_not_found(from),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        match self.entries.lock().unwrap().remove(path) {
+            Some(FakeEntry::File(_)) | Some(FakeEntry::Symlink(_)) => Ok(()),
+            _ => bail_not_found(path),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        match self.entries.lock().unwrap().remove(path) {
+            Some(FakeEntry::Dir) => Ok(()),
+            _ => bail_not_found(path),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<Metadata>> {
+        Ok(match self.entries.lock().unwrap().get(path) {
+            Some(FakeEntry::Dir) => Some(Metadata { is_dir: true }),
+            Some(FakeEntry::File(_)) => Some(Metadata { is_dir: false }),
+            Some(FakeEntry::Symlink(_)) => Some(Metadata { is_dir: false }),
+            None => None,
+        })
+    }
+
+    fn copy_chunks(&self, from: &Path, to: &Path, ranges: &[ChunkRange]) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let source = match entries.get(from) {
+            Some(FakeEntry::File(contents)) => contents.clone(),
+            _ => bail_not_found(from)?,
+        };
+
+        let mut destination = match entries.get(to) {
+            Some(FakeEntry::File(contents)) => contents.clone(),
+            _ => Vec::new(),
+        };
+        destination.resize(source.len(), 0);
+
+        for range in ranges {
+            let start = range.offset as usize;
+            let end = start + range.len as usize;
+            destination[start..end].copy_from_slice(&source[start..end]);
+        }
+
+        entries.insert(to.to_path_buf(), FakeEntry::File(destination));
+        Ok(())
+    }
+
+    fn create_symlink(&self, path: &Path, target: &Path) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), FakeEntry::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &Path, _mode: u32) -> Result<()> {
+        if self.entries.lock().unwrap().contains_key(path) {
+            Ok(())
+        } else {
+            bail_not_found(path)
+        }
+    }
+}
+
+#[cfg(test)]
+fn bail_not_found<T>(path: &Path) -> Result<T> {
+    anyhow::bail!("{} not found in FakeFs", path.display())
+}
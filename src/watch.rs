@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// Watch `directory` for changes and emit batches of the paths that changed,
+/// modeled on the debounced event stream Zed's `fs.rs` builds on top of
+/// `notify`. A burst of raw events (an editor's write-then-rename save, a
+/// `cp -r` of many files) is coalesced into a single batch emitted after
+/// `debounce` of quiet, so a caller does one incremental update per batch
+/// instead of one per raw event.
+///
+/// Batches carry paths, not event kinds: whether a path was created,
+/// modified or removed is determined by re-`stat`ing it when the batch is
+/// handled, which is simpler than trusting a specific backend's event
+/// classification and degrades safely if an event is missed.
+pub fn watch(directory: &Path, debounce: Duration) -> Result<Receiver<Vec<PathBuf>>> {
+    let (raw_tx, raw_rx) = channel();
+    let mut watcher: RecommendedWatcher =
+        notify::recommended_watcher(raw_tx).context("Failed to create filesystem watcher")?;
+    watcher
+        .watch(directory, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", directory.display()))?;
+
+    let (batch_tx, batch_rx) = channel();
+    std::thread::spawn(move || {
+        // kept alive for the thread's lifetime; dropping it would stop the watch
+        let _watcher = watcher;
+        while let Ok(first) = raw_rx.recv() {
+            let mut paths: HashSet<PathBuf> = HashSet::new();
+            collect_paths(&mut paths, first);
+            while let Ok(event) = raw_rx.recv_timeout(debounce) {
+                collect_paths(&mut paths, event);
+            }
+
+            if batch_tx.send(paths.into_iter().collect()).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(batch_rx)
+}
+
+fn collect_paths(paths: &mut HashSet<PathBuf>, event: notify::Result<notify::Event>) {
+    if let Ok(event) = event {
+        paths.extend(event.paths);
+    }
+}